@@ -0,0 +1,70 @@
+//! Implementation of [`TaskManager`]
+//!
+//! The ready queue of runnable tasks. `Processor` only tracks the task
+//! currently executing and the idle control flow; adding tasks to and
+//! removing them from the runnable pool goes through this module instead, so
+//! that fork, yield and blocking can all grow or shrink the ready set.
+
+use super::TaskControlBlock;
+use crate::sync::UPSafeCell;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use lazy_static::*;
+
+/// A FIFO-ish queue of tasks that are ready to run
+pub struct TaskManager {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl TaskManager {
+    ///Create an empty TaskManager
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+    /// Add a task to the back of the ready queue
+    pub fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    /// Pop the task at the front of the ready queue
+    pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+    /// Remove and return the runnable task with the smallest stride; see
+    /// [`super::task::TaskControlBlockInner::pass`] for why wrapping comparison is safe here
+    pub fn fetch_min(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let min_idx = self
+            .ready_queue
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let a_stride = a.inner_exclusive_access().stride;
+                let b_stride = b.inner_exclusive_access().stride;
+                (a_stride.wrapping_sub(b_stride) as i64).cmp(&0)
+            })
+            .map(|(idx, _)| idx)?;
+        self.ready_queue.remove(min_idx)
+    }
+}
+
+lazy_static! {
+    /// The global ready queue
+    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
+        unsafe { UPSafeCell::new(TaskManager::new()) };
+}
+
+/// Add a task to the back of the ready queue
+pub fn add_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.exclusive_access().add(task);
+}
+
+/// Pop the task at the front of the ready queue
+pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
+    TASK_MANAGER.exclusive_access().fetch()
+}
+
+/// Remove and return the runnable task with the smallest stride
+pub fn fetch_min_task() -> Option<Arc<TaskControlBlock>> {
+    TASK_MANAGER.exclusive_access().fetch_min()
+}
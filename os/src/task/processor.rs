@@ -5,11 +5,12 @@
 //! and the replacement and transfer of control flow of different applications are executed.
 
 use super::{__switch, TaskInfo};
-use super::{fetch_task, TaskStatus, fetch_min_task};
+use super::manager::{add_task, fetch_min_task};
+use super::TaskStatus;
 use super::{TaskContext, TaskControlBlock};
 use crate::sync::UPSafeCell;
 use crate::trap::TrapContext;
-use crate::timer::get_time_ms;
+use crate::timer::{get_time_ms, get_time_us};
 use alloc::sync::Arc;
 use lazy_static::*;
 
@@ -52,9 +53,19 @@ impl Processor {
         current_task.task_info.set_status(status);
         if refresh_flag {
             current_task.task_info.increase_syscall_time(syscall_idx);
+            current_task.syscall_enter_time = get_time_us();
         }
     }
 
+    /// Charge the elapsed time since the matching `refresh_task_info` call to
+    /// syscall `syscall_idx` on the current task
+    pub fn finish_task_info(&self, syscall_idx: usize) {
+        let inner = self.current().unwrap();
+        let mut current_task = inner.inner_exclusive_access();
+        let elapsed = get_time_us() - current_task.syscall_enter_time;
+        current_task.task_info.add_syscall_elapsed(syscall_idx, elapsed);
+    }
+
     pub fn get_current_task_info(&self) ->TaskInfo{
         let inner = self.current().unwrap();
         let mut current_task = inner.inner_exclusive_access();
@@ -81,6 +92,13 @@ impl Processor {
 
         current_task.m_unmap(start, len)
     }
+
+    pub fn current_task_set_priority(&self, priority: usize) -> isize {
+        let inner = self.current().unwrap();
+        let mut current_task = inner.inner_exclusive_access();
+
+        current_task.set_priority(priority)
+    }
 }
 
 lazy_static! {
@@ -98,6 +116,9 @@ pub fn run_tasks() {
             let mut task_inner = task.inner_exclusive_access();
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
+            // advance the task's stride by its pass so the next `fetch_min_task`
+            // sees an up-to-date ordering
+            task_inner.stride = task_inner.stride.wrapping_add(task_inner.pass());
             // release coming task_inner manually
             drop(task_inner);
             // release coming task TCB manually
@@ -147,6 +168,20 @@ pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
     }
 }
 
+/// Suspend the current task: mark it `Ready`, hand it back to the manager's
+/// ready queue instead of dropping it, and yield to the idle control flow so
+/// `run_tasks` can schedule something else.
+pub fn suspend_current_and_yield() {
+    let task = take_current_task().unwrap();
+    let task_cx_ptr = {
+        let mut inner = task.inner_exclusive_access();
+        inner.task_status = TaskStatus::Ready;
+        &mut inner.task_cx as *mut TaskContext
+    };
+    add_task(task);
+    schedule(task_cx_ptr);
+}
+
 pub fn get_current_processor_info() -> TaskInfo {
     PROCESSOR.exclusive_access().get_current_task_info()
 }
@@ -155,10 +190,20 @@ pub fn refresh_processor_syscall_times(syscall: usize){
     PROCESSOR.exclusive_access().refresh_task_info(syscall, true);
 }
 
+/// Charge the elapsed time of the just-finished syscall to the current task
+pub fn finish_processor_syscall_times(syscall: usize){
+    PROCESSOR.exclusive_access().finish_task_info(syscall);
+}
+
 pub fn current_processor_m_map(start: usize, len: usize, port: usize) -> isize {
     PROCESSOR.exclusive_access().current_task_m_map(start, len, port)
 }
 
 pub fn current_processor_m_unmap(start: usize, len: usize) -> isize {
     PROCESSOR.exclusive_access().current_task_m_unmap(start, len)
+}
+
+/// Set the priority of the current task. Returns -1 if `priority < 2`.
+pub fn current_processor_set_priority(priority: usize) -> isize {
+    PROCESSOR.exclusive_access().current_task_set_priority(priority)
 }
\ No newline at end of file
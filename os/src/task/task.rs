@@ -1,14 +1,44 @@
 //! Types related to task management
 use crate::config::MAX_SYSCALL_NUM;
+use super::pid::{pid_alloc, KernelStack, PidHandle};
 use super::TaskContext;
-use crate::config::TRAP_CONTEXT_BASE;
+use crate::config::{PAGE_SIZE, TRAP_CONTEXT_BASE};
 use crate::mm::{
-    kernel_stack_position, MapPermission, MemorySet, PhysPageNum, VirtAddr, VirtPageNum, KERNEL_SPACE
+    MapPermission, MemorySet, PhysPageNum, VirtAddr, VirtPageNum, KERNEL_SPACE
 };
+use crate::sync::UPSafeCell;
 use crate::trap::{trap_handler, TrapContext};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cell::RefMut;
+
+/// Default priority assigned to a newly created task
+const DEFAULT_PRIORITY: usize = 16;
+
+/// Minimum allowed priority; see [`BIG_STRIDE`] for why this floor matters
+const MIN_PRIORITY: usize = 2;
+
+/// The numerator of every task's `pass`; strides themselves wrap at `usize::MAX`,
+/// not at this value. Because `priority >= MIN_PRIORITY`, each `pass`
+/// (`BIG_STRIDE / priority`) is at most `BIG_STRIDE / 2`, which keeps the spread
+/// between any two runnable strides within `BIG_STRIDE` and lets us compare
+/// strides with wrapping arithmetic even after one overflow wrap.
+pub const BIG_STRIDE: usize = 0xFFFF_FFFF;
 
 /// The task control block (TCB) of a task.
 pub struct TaskControlBlock {
+    /// Process identifier, recycled automatically when dropped
+    pub pid: PidHandle,
+
+    /// Kernel stack mapped for this task, keyed by `pid`
+    pub kernel_stack: KernelStack,
+
+    /// Mutable state, exclusively accessed through `inner_exclusive_access`
+    inner: UPSafeCell<TaskControlBlockInner>,
+}
+
+/// The mutable part of a [`TaskControlBlock`]
+pub struct TaskControlBlockInner {
     pub task_info : TaskInfo,
     /// Save task context
     pub task_cx: TaskContext,
@@ -32,19 +62,43 @@ pub struct TaskControlBlock {
     pub program_brk: usize,
 
     pub task_start_time: usize,
+
+    /// Scheduling priority used to compute `pass`; minimum is `MIN_PRIORITY`
+    pub priority: usize,
+
+    /// Stride accumulated so far under the stride scheduling algorithm
+    pub stride: usize,
+
+    /// The parent task, if any. A weak reference so the parent's `Arc` refcount
+    /// is not kept alive solely by its children.
+    pub parent: Option<Weak<TaskControlBlock>>,
+
+    /// Live child tasks spawned by this task via `fork`
+    pub children: Vec<Arc<TaskControlBlock>>,
+
+    /// Exit code reported to a future `waitpid` once this task becomes a zombie
+    pub exit_code: i32,
+
+    /// Timestamp (us) at which the syscall currently in flight was entered,
+    /// used to compute the elapsed time charged to it on return
+    pub syscall_enter_time: usize,
 }
 
 impl TaskControlBlock {
-    /// get the trap context
-    pub fn get_trap_cx(&self) -> &'static mut TrapContext {
-        self.trap_cx_ppn.get_mut()
+    /// Exclusively access the mutable inner state of the task
+    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
+        self.inner.exclusive_access()
     }
     /// get the user token
     pub fn get_user_token(&self) -> usize {
-        self.memory_set.token()
+        self.inner_exclusive_access().memory_set.token()
+    }
+    /// the process identifier of this task
+    pub fn getpid(&self) -> usize {
+        self.pid.0
     }
     /// Based on the elf info in program, build the contents of task in a new address space
-    pub fn new(elf_data: &[u8], app_id: usize) -> Self {
+    pub fn new(elf_data: &[u8]) -> Self {
         // memory_set with elf program headers/trampoline/trap context/user stack
         let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
         let trap_cx_ppn = memory_set
@@ -52,26 +106,35 @@ impl TaskControlBlock {
             .unwrap()
             .ppn();
         let task_status = TaskStatus::Ready;
-        // map a kernel-stack in kernel space
-        let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(app_id);
-        KERNEL_SPACE.exclusive_access().insert_framed_area(
-            kernel_stack_bottom.into(),
-            kernel_stack_top.into(),
-            MapPermission::R | MapPermission::W,
-        );
+        // allocate a pid and a kernel stack mapped for it in kernel space
+        let pid_handle = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack_top = kernel_stack.get_top();
         let task_control_block = Self {
-            task_info: TaskInfo::new(task_status),
-            task_status: task_status,
-            task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-            memory_set,
-            trap_cx_ppn,
-            base_size: user_sp,
-            heap_bottom: user_sp,
-            program_brk: user_sp,
-            task_start_time: 0
+            pid: pid_handle,
+            kernel_stack,
+            inner: unsafe {
+                UPSafeCell::new(TaskControlBlockInner {
+                    task_info: TaskInfo::new(task_status),
+                    task_status,
+                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                    memory_set,
+                    trap_cx_ppn,
+                    base_size: user_sp,
+                    heap_bottom: user_sp,
+                    program_brk: user_sp,
+                    task_start_time: 0,
+                    priority: DEFAULT_PRIORITY,
+                    stride: 0,
+                    parent: None,
+                    children: Vec::new(),
+                    exit_code: 0,
+                    syscall_enter_time: 0,
+                })
+            },
         };
         // prepare TrapContext in user space
-        let trap_cx = task_control_block.get_trap_cx();
+        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
         *trap_cx = TrapContext::app_init_context(
             entry_point,
             user_sp,
@@ -81,6 +144,81 @@ impl TaskControlBlock {
         );
         task_control_block
     }
+
+    /// Fork a child task with a deep copy of this task's address space
+    pub fn fork(self: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
+        let mut parent_inner = self.inner_exclusive_access();
+        let memory_set = MemorySet::from_existed_user(&parent_inner.memory_set);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT_BASE).into())
+            .unwrap()
+            .ppn();
+        let pid_handle = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack_top = kernel_stack.get_top();
+        let task_control_block = Arc::new(TaskControlBlock {
+            pid: pid_handle,
+            kernel_stack,
+            inner: unsafe {
+                UPSafeCell::new(TaskControlBlockInner {
+                    task_info: TaskInfo::new(TaskStatus::Ready),
+                    task_status: TaskStatus::Ready,
+                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                    memory_set,
+                    trap_cx_ppn,
+                    base_size: parent_inner.base_size,
+                    heap_bottom: parent_inner.heap_bottom,
+                    program_brk: parent_inner.program_brk,
+                    task_start_time: 0,
+                    priority: parent_inner.priority,
+                    stride: 0,
+                    parent: Some(Arc::downgrade(self)),
+                    children: Vec::new(),
+                    exit_code: 0,
+                    syscall_enter_time: 0,
+                })
+            },
+        });
+        parent_inner.children.push(Arc::clone(&task_control_block));
+        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
+        *trap_cx = *parent_inner.get_trap_cx();
+        trap_cx.kernel_sp = kernel_stack_top;
+        trap_cx.x[10] = 0; // a0 holds fork's return value, 0 for the child
+        task_control_block
+    }
+
+    /// Rebuild this task's address space in place from a freshly loaded elf image
+    pub fn exec(&self, elf_data: &[u8]) {
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT_BASE).into())
+            .unwrap()
+            .ppn();
+
+        let mut inner = self.inner_exclusive_access();
+        inner.memory_set = memory_set;
+        inner.trap_cx_ppn = trap_cx_ppn;
+        inner.base_size = user_sp;
+        inner.heap_bottom = user_sp;
+        inner.program_brk = user_sp;
+
+        let trap_cx = inner.get_trap_cx();
+        *trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.exclusive_access().token(),
+            self.kernel_stack.get_top(),
+            trap_handler as usize,
+        );
+    }
+}
+
+impl TaskControlBlockInner {
+    /// get the trap context
+    pub fn get_trap_cx(&self) -> &'static mut TrapContext {
+        self.trap_cx_ppn.get_mut()
+    }
+
     /// change the location of the program break. return None if failed.
     pub fn change_program_brk(&mut self, size: i32) -> Option<usize> {
         let old_break = self.program_brk;
@@ -104,36 +242,91 @@ impl TaskControlBlock {
     }
 
     pub fn m_map(&mut self, start:usize, len: usize, port:usize) ->isize{
-        if start % 4096 == 0 && (port & !0x7 ==0) && (port & 0x7 != 0) {
-            // self.memory_set.insert_framed_area(VirtAddr::from(start)
-            // , VirtAddr(start + len)
-            // , MapPermission::from_bits((port <<1 | 0x18) as u8).unwrap()
-            // );
-            // 0
-            self.memory_set.insert_framed_area(VirtAddr::from(start), VirtAddr::from(start + len), MapPermission::from_usize((port << 1) | 0x18))
-            
-
-        }else{
-            -1
+        if len == 0 || start % PAGE_SIZE != 0 || (port & !0x7 != 0) || (port & 0x7 == 0) {
+            return -1;
         }
-
+        let end = start + (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let start_vpn = VirtAddr::from(start).floor();
+        let end_vpn = VirtAddr::from(end).ceil();
+        // an already-mapped page anywhere in the range must abort the whole request
+        for vpn in start_vpn.0..end_vpn.0 {
+            if self
+                .memory_set
+                .translate(VirtPageNum::from(vpn))
+                .map_or(false, |pte| pte.is_valid())
+            {
+                return -1;
+            }
+        }
+        self.memory_set.insert_framed_area(
+            VirtAddr::from(start),
+            VirtAddr::from(end),
+            MapPermission::from_usize((port << 1) | 0x18),
+        )
     }
 
     pub fn m_unmap(&mut self, start: usize, len: usize) -> isize{
-        if start % 4096 == 0 && len % 4096 == 0{
-            self.memory_set.remove_area(VirtAddr::from(start), VirtAddr::from(start + len))
-        //    let mut result : isize = 0;
-        //     for start_vpn in start ..=start+len { 
-                
-        //         result = self.memory_set.remove_area_with_start_vpn(VirtPageNum::from(start_vpn));
-        //     }
-
-        //     // 0
-        //     result
-        }else{
-            -1
+        if len == 0 || start % PAGE_SIZE != 0 {
+            return -1;
+        }
+        let end = start + (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let start_vpn = VirtAddr::from(start).floor();
+        let end_vpn = VirtAddr::from(end).ceil();
+        // every page in the range must already be mapped before we unmap any of it
+        for vpn in start_vpn.0..end_vpn.0 {
+            if !self
+                .memory_set
+                .translate(VirtPageNum::from(vpn))
+                .map_or(false, |pte| pte.is_valid())
+            {
+                return -1;
+            }
+        }
+        self.memory_set.remove_area(VirtAddr::from(start), VirtAddr::from(end))
+    }
+
+    /// Set the task's scheduling priority. Returns -1 if below `MIN_PRIORITY`.
+    pub fn set_priority(&mut self, priority: usize) -> isize {
+        if priority < MIN_PRIORITY {
+            return -1;
         }
+        self.priority = priority;
+        priority as isize
+    }
+
+    /// The stride increment applied each time this task is scheduled to run;
+    /// see [`BIG_STRIDE`] for why comparing strides with wrapping arithmetic is safe
+    pub fn pass(&self) -> usize {
+        BIG_STRIDE / self.priority
+    }
 
+    /// Whether this task has exited and is waiting to be reaped by `waitpid`
+    pub fn is_zombie(&self) -> bool {
+        self.task_status == TaskStatus::Exited
+    }
+
+    /// Reap a zombie child matching `pid` (-1 for any); `Err(-1)` if none, `Err(-2)` if not exited yet
+    pub fn waitpid(&mut self, pid: isize) -> Result<(usize, i32), isize> {
+        if !self
+            .children
+            .iter()
+            .any(|child| pid == -1 || pid as usize == child.getpid())
+        {
+            return Err(-1);
+        }
+        let zombie = self.children.iter().enumerate().find(|(_, child)| {
+            child.inner_exclusive_access().is_zombie() && (pid == -1 || pid as usize == child.getpid())
+        });
+        match zombie {
+            Some((idx, _)) => {
+                let child = self.children.remove(idx);
+                assert_eq!(Arc::strong_count(&child), 1);
+                let found_pid = child.getpid();
+                let exit_code = child.inner_exclusive_access().exit_code;
+                Ok((found_pid, exit_code))
+            }
+            None => Err(-2),
+        }
     }
 
 }
@@ -143,8 +336,9 @@ impl TaskControlBlock {
 pub struct TaskInfo {
     /// Task status in it's life cycle
     status: TaskStatus,
-    /// The numbers of syscall called by task
-    syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// For each syscall index, the number of times it has been called and the
+    /// accumulated microseconds spent inside it
+    syscall_times: [(u32, usize); MAX_SYSCALL_NUM],
     /// Total running time of task
     time: usize,
 }
@@ -163,11 +357,11 @@ pub enum TaskStatus {
 }
 impl TaskInfo{
     pub fn new(ts: TaskStatus)->Self{
-        Self { 
-            status: ts, 
-            //initialize syscall number as zero
-            syscall_times: [0; MAX_SYSCALL_NUM], 
-            time: 0, 
+        Self {
+            status: ts,
+            //initialize syscall counts and times as zero
+            syscall_times: [(0, 0); MAX_SYSCALL_NUM],
+            time: 0,
         }
     }
     pub fn set_status(&mut self, status: TaskStatus){
@@ -176,7 +370,14 @@ impl TaskInfo{
 
     pub fn increase_syscall_time(&mut self, idx: usize){
         if idx < MAX_SYSCALL_NUM {
-            self.syscall_times[idx] += 1;
+            self.syscall_times[idx].0 += 1;
+        }
+    }
+
+    /// Add `elapsed` microseconds to the accumulated time of syscall `idx`
+    pub fn add_syscall_elapsed(&mut self, idx: usize, elapsed: usize){
+        if idx < MAX_SYSCALL_NUM {
+            self.syscall_times[idx].1 += elapsed;
         }
     }
     pub fn set_run_time(&mut self, t: usize){